@@ -1,13 +1,18 @@
 //! Implementation of [`PageTableEntry`] and [`PageTable`].
 
 use super::{frame_alloc, FrameTracker, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use crate::sync::UPSafeCell;
+use alloc::collections::BTreeMap;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::*;
+use lazy_static::lazy_static;
 
 bitflags! {
     /// page table entry flags
-    pub struct PTEFlags: u8 {
+    ///
+    /// Bits 8-9 are reserved for OS use, so `COW`/`LAZY` live there.
+    pub struct PTEFlags: usize {
         const V = 1 << 0;
         const R = 1 << 1;
         const W = 1 << 2;
@@ -16,6 +21,51 @@ bitflags! {
         const G = 1 << 5;
         const A = 1 << 6;
         const D = 1 << 7;
+        /// marks a page as copy-on-write: shared, read-only until the next store fault
+        const COW = 1 << 8;
+        /// marks a page as lazily (demand-paged) mapped: not yet backed by a frame
+        const LAZY = 1 << 9;
+    }
+}
+
+lazy_static! {
+    /// Reference count of each physical frame shared by copy-on-write mappings.
+    /// A frame absent from this map is solely owned (implicit count of 1).
+    #[allow(unused)]
+    static ref COW_REF_COUNT: UPSafeCell<BTreeMap<usize, usize>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+    /// Owns each frame's `FrameTracker` for as long as it is COW-shared, so
+    /// no single owning `PageTable` can free it out from under another.
+    #[allow(unused)]
+    static ref COW_FRAMES: UPSafeCell<BTreeMap<usize, FrameTracker>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+}
+
+#[allow(unused)]
+fn frame_ref_count(ppn: PhysPageNum) -> usize {
+    *COW_REF_COUNT.exclusive_access().get(&ppn.0).unwrap_or(&1)
+}
+
+/// Record that `ppn` has gained another holder.
+#[allow(unused)]
+fn frame_ref_inc(ppn: PhysPageNum) {
+    let mut inner = COW_REF_COUNT.exclusive_access();
+    let count = inner.get(&ppn.0).copied().unwrap_or(1);
+    inner.insert(ppn.0, count + 1);
+}
+
+/// Record that one holder of `ppn` has given it up, dropping the bookkeeping
+/// entry entirely once a single owner remains.
+#[allow(unused)]
+fn frame_ref_dec(ppn: PhysPageNum) {
+    let mut inner = COW_REF_COUNT.exclusive_access();
+    match inner.get(&ppn.0).copied() {
+        Some(count) if count > 2 => {
+            inner.insert(ppn.0, count - 1);
+        }
+        _ => {
+            inner.remove(&ppn.0);
+        }
     }
 }
 
@@ -31,7 +81,7 @@ impl PageTableEntry {
     /// Create a new page table entry
     pub fn new(ppn: PhysPageNum, flags: PTEFlags) -> Self {
         PageTableEntry {
-            bits: ppn.0 << 10 | flags.bits as usize,
+            bits: ppn.0 << 10 | flags.bits,
         }
     }
     /// Create an empty page table entry
@@ -44,7 +94,7 @@ impl PageTableEntry {
     }
     /// Get the flags from the page table entry
     pub fn flags(&self) -> PTEFlags {
-        PTEFlags::from_bits(self.bits as u8).unwrap()
+        PTEFlags::from_bits(self.bits & ((1usize << 10) - 1)).unwrap()
     }
     /// The page pointered by page table entry is valid?
     pub fn is_valid(&self) -> bool {
@@ -62,6 +112,12 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    /// A valid PTE with any of R/W/X set is a leaf, at whatever level it
+    /// appears: Sv39 allows a 2 MiB (level 1) or 1 GiB (level 0) superpage
+    /// leaf as well as the usual 4 KiB (level 2) one.
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && (self.readable() || self.writable() || self.executable())
+    }
 }
 
 /// page table structure
@@ -89,12 +145,20 @@ impl PageTable {
     }
     /// Find PageTableEntry by VirtPageNum, create a frame for a 4KB page table if not exist
     fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+        self.find_pte_create_at(vpn, 2)
+    }
+
+    /// Like [`find_pte_create`](Self::find_pte_create), but stops descending
+    /// at table `level` instead of always walking down to the 4 KiB leaf
+    /// level (2). Used by [`map_huge`](Self::map_huge) to install a
+    /// superpage leaf directly into an intermediate table.
+    fn find_pte_create_at(&mut self, vpn: VirtPageNum, level: usize) -> Option<&mut PageTableEntry> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
         let mut result: Option<&mut PageTableEntry> = None;
         for (i, idx) in idxs.iter().enumerate() {
             let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
+            if i == level {
                 result = Some(pte);
                 break;
             }
@@ -122,6 +186,11 @@ impl PageTable {
             if !pte.is_valid() {
                 return None;
             }
+            if pte.is_leaf() {
+                // a superpage leaf at an intermediate level: stop early
+                result = Some(pte);
+                break;
+            }
             ppn = pte.ppn();
         }
         result
@@ -140,6 +209,42 @@ impl PageTable {
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
     }
+    /// Install a superpage leaf at table `level` instead of descending all
+    /// the way to a 4 KiB (level 2) leaf: `level` 1 installs a 2 MiB leaf in
+    /// the middle table, `level` 0 a 1 GiB leaf in the root table. `vpn`/`ppn`
+    /// must already be aligned to the chosen level's granularity.
+    ///
+    /// This is just the leaf-installation primitive, plus the early-stop
+    /// handling in `is_leaf`/`find_pte` that lets lookups recognize a
+    /// superpage above level 2 (`find_pte_create_at` has no such check and
+    /// always descends to `level`) — it is not wired into
+    /// `sys_mmap` or any kernel identity-mapping path, so the "back big
+    /// mmap requests with fewer page-table frames" payoff isn't delivered
+    /// by this series. That needs a physical allocator that can hand back
+    /// contiguous, aligned runs of frames, which this tree's `frame_alloc`
+    /// doesn't do.
+    #[allow(unused)]
+    pub fn map_huge(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags, level: usize) {
+        assert!(level < 2, "level 2 is the native 4KiB leaf, use map() for that");
+        let align_bits = 9 * (2 - level);
+        assert_eq!(
+            vpn.0 & ((1 << align_bits) - 1),
+            0,
+            "vpn {:?} not aligned to the level-{} superpage granularity",
+            vpn,
+            level
+        );
+        assert_eq!(
+            ppn.0 & ((1 << align_bits) - 1),
+            0,
+            "ppn {:?} not aligned to the level-{} superpage granularity",
+            ppn,
+            level
+        );
+        let pte = self.find_pte_create_at(vpn, level).unwrap();
+        assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
+        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+    }
     /// remove the map between virtual page number and physical page number
     #[allow(unused)]
     pub fn unmap(&mut self, vpn: VirtPageNum) {
@@ -151,6 +256,128 @@ impl PageTable {
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.find_pte(vpn).map(|pte| *pte)
     }
+    /// Change the permission bits of an already-mapped page in place,
+    /// keeping its physical frame and `V` bit. Returns `None` if `vpn` is
+    /// unmapped or `COW` (reflagging a still-shared frame to `W` would let
+    /// this table write through to a frame another table still holds, the
+    /// very thing `COW` exists to prevent), so callers (e.g. `sys_mprotect`)
+    /// can check every page in a range before applying any changes.
+    pub fn set_flags(&mut self, vpn: VirtPageNum, flags: PTEFlags) -> Option<()> {
+        let pte = self.find_pte(vpn)?;
+        if !pte.is_valid() || pte.flags().contains(PTEFlags::COW) {
+            return None;
+        }
+        let ppn = pte.ppn();
+        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        Some(())
+    }
+    /// Remove and return the `FrameTracker` owning `ppn`, if this table is
+    /// the one currently holding it. Used to hand a frame's ownership over
+    /// to [`COW_FRAMES`] the moment it starts being shared.
+    #[allow(unused)]
+    fn take_frame(&mut self, ppn: PhysPageNum) -> Option<FrameTracker> {
+        let idx = self.frames.iter().position(|f| f.ppn == ppn)?;
+        Some(self.frames.swap_remove(idx))
+    }
+    /// Install `ppn` as a copy-on-write mapping for `vpn`: `W` is cleared
+    /// and `COW` is set, so the next store traps into [`copy_on_write`](Self::copy_on_write).
+    /// A fresh mapping (e.g. the child's, during fork) registers a new
+    /// holder; re-flagging an existing one onto the same `ppn` does not.
+    /// Does not by itself move frame ownership into [`COW_FRAMES`] — see
+    /// [`fork_cow`] for the full, sound fork sequence.
+    ///
+    /// Not currently called from anywhere: `fork` lives on `Task`, which
+    /// isn't part of this tree, so there's no call site to wire this into
+    /// yet. Kept as a primitive alongside `copy_on_write`/`fork_cow`.
+    #[allow(unused)]
+    pub fn map_cow(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, orig_flags: PTEFlags) {
+        let flags = (orig_flags - PTEFlags::W) | PTEFlags::COW | PTEFlags::V;
+        let pte = self.find_pte_create(vpn).unwrap();
+        let already_shared = pte.is_valid() && pte.ppn() == ppn;
+        *pte = PageTableEntry::new(ppn, flags);
+        if !already_shared {
+            frame_ref_inc(ppn);
+        }
+    }
+    /// Resolve a store fault on a `COW` page. Returns `false` if `vpn` is
+    /// not `COW`, meaning the fault is a genuine access violation the
+    /// caller should handle by killing the task. Unwired for the same
+    /// reason as [`map_cow`](Self::map_cow) — no trap handler in this tree to call it.
+    #[allow(unused)]
+    pub fn copy_on_write(&mut self, vpn: VirtPageNum) -> bool {
+        let (old_ppn, old_flags) = match self.find_pte(vpn) {
+            Some(pte) if pte.flags().contains(PTEFlags::COW) => (pte.ppn(), pte.flags()),
+            _ => return false,
+        };
+        let flags = (old_flags - PTEFlags::COW) | PTEFlags::W;
+        if frame_ref_count(old_ppn) <= 1 {
+            // sole remaining holder: reclaim the frame from COW_FRAMES in place
+            *self.find_pte(vpn).unwrap() = PageTableEntry::new(old_ppn, flags);
+            if let Some(frame) = COW_FRAMES.exclusive_access().remove(&old_ppn.0) {
+                self.frames.push(frame);
+            }
+        } else {
+            let new_frame = frame_alloc().unwrap();
+            let new_ppn = new_frame.ppn;
+            new_ppn
+                .get_bytes_array()
+                .copy_from_slice(old_ppn.get_bytes_array());
+            *self.find_pte(vpn).unwrap() = PageTableEntry::new(new_ppn, flags);
+            self.frames.push(new_frame);
+            frame_ref_dec(old_ppn);
+        }
+        true
+    }
+    /// Record a demand-paged ("lazy") mapping for `vpn` without backing it
+    /// with a frame: the PTE is left invalid but carries `LAZY` plus the
+    /// intended `flags` (R/W/X/U) in the reserved/permission bits, so the
+    /// fault handler can later tell a satisfiable lazy fault from a genuine
+    /// access violation without any extra per-task bookkeeping.
+    ///
+    /// Not currently wired to any caller: resolving a lazy fault needs a
+    /// trap-handler hook this tree doesn't have, so `sys_mmap` allocates
+    /// eagerly instead. Kept as a primitive for when that hook exists.
+    #[allow(unused)]
+    pub fn map_lazy(&mut self, vpn: VirtPageNum, flags: PTEFlags) {
+        let pte = self.find_pte_create(vpn).unwrap();
+        *pte = PageTableEntry::new(PhysPageNum(0), (flags - PTEFlags::V) | PTEFlags::LAZY);
+    }
+    /// Is `vpn` a pending lazy mapping installed by [`PageTable::map_lazy`]?
+    #[allow(unused)]
+    pub fn is_lazy(&self, vpn: VirtPageNum) -> bool {
+        self.find_pte(vpn)
+            .map(|pte| !pte.is_valid() && pte.flags().contains(PTEFlags::LAZY))
+            .unwrap_or(false)
+    }
+    /// Resolve a page fault against a lazily-mapped page. `access` is the
+    /// permission the faulting instruction required (e.g. `PTEFlags::W` for
+    /// a store). Returns `false` if `vpn` is not a pending lazy mapping, or
+    /// the region's stored flags don't permit `access` — either way this is
+    /// a genuine access violation the caller should handle by killing the task.
+    #[allow(unused)]
+    pub fn lazy_alloc(&mut self, vpn: VirtPageNum, access: PTEFlags) -> bool {
+        let flags = match self.find_pte(vpn) {
+            Some(pte) if !pte.is_valid() && pte.flags().contains(PTEFlags::LAZY) => pte.flags(),
+            _ => return false,
+        };
+        if !flags.contains(access) {
+            return false;
+        }
+        let frame = frame_alloc().unwrap();
+        let ppn = frame.ppn;
+        self.frames.push(frame);
+        let real_flags = (flags - PTEFlags::LAZY) | PTEFlags::V;
+        *self.find_pte(vpn).unwrap() = PageTableEntry::new(ppn, real_flags);
+        true
+    }
+    /// Remove any mapping at `vpn`, resident or pending-lazy. Used by
+    /// `munmap` so it can drop a lazy region that was never actually
+    /// faulted in, as well as one that was.
+    pub fn unmap_any(&mut self, vpn: VirtPageNum) {
+        if let Some(pte) = self.find_pte(vpn) {
+            *pte = PageTableEntry::empty();
+        }
+    }
     /// get the token from the page table
     pub fn token(&self) -> usize {
         8usize << 60 | self.root_ppn.0
@@ -165,6 +392,54 @@ impl PageTable {
         self.frames.push(frame);
         Some(())
     }
+    /// Recursively walk all three Sv39 levels and yield every valid leaf
+    /// PTE (an ordinary 4 KiB leaf, or a 2 MiB / 1 GiB superpage installed by
+    /// [`map_huge`](Self::map_huge)) together with its `VirtPageNum`.
+    /// Non-leaf intermediate entries are skipped, and an unmapped
+    /// sub-table simply yields nothing for the VPN range beneath it.
+    pub fn iter_mapped(&self) -> alloc::vec::IntoIter<(VirtPageNum, PageTableEntry)> {
+        let mut out = Vec::new();
+        Self::walk_leaves(self.root_ppn, 0, 0, &mut out);
+        out.into_iter()
+    }
+    fn walk_leaves(
+        ppn: PhysPageNum,
+        level: usize,
+        vpn_prefix: usize,
+        out: &mut Vec<(VirtPageNum, PageTableEntry)>,
+    ) {
+        for (idx, pte) in ppn.get_pte_array().iter().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+            let vpn_prefix = (vpn_prefix << 9) | idx;
+            if level == 2 || pte.is_leaf() {
+                let shift = 9 * (2 - level);
+                out.push((VirtPageNum(vpn_prefix << shift), *pte));
+            } else {
+                Self::walk_leaves(pte.ppn(), level + 1, vpn_prefix, out);
+            }
+        }
+    }
+}
+
+/// Share `vpn`'s frame between `parent` and `child` as copy-on-write,
+/// transferring the frame's ownership into [`COW_FRAMES`] the first time
+/// it's shared so neither table's `Drop` can free it out from under the
+/// other.
+///
+/// Not called anywhere yet: `fork`'s deep-copy loop lives on `Task`, which
+/// isn't part of this tree, so there's no real call site to wire this into.
+/// "Fork shares frames instead of deep-copying" is not actually implemented
+/// by this series — this is a primitive for whenever that call site exists.
+#[allow(unused)]
+pub fn fork_cow(parent: &mut PageTable, child: &mut PageTable, vpn: VirtPageNum, orig_flags: PTEFlags) {
+    let ppn = parent.find_pte(vpn).unwrap().ppn();
+    if let Some(frame) = parent.take_frame(ppn) {
+        COW_FRAMES.exclusive_access().insert(ppn.0, frame);
+    }
+    parent.map_cow(vpn, ppn, orig_flags);
+    child.map_cow(vpn, ppn, orig_flags);
 }
 
 /// Translate&Copy a ptr[u8] array with LENGTH len to a mutable u8 Vec through page table
@@ -191,6 +466,33 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
     v
 }
 
+/// Write `value` to the user-space object at `ptr`, splitting the write across
+/// pages if `T` straddles a page boundary.
+pub fn copy_to_user<T: Copy>(token: usize, ptr: *mut T, value: &T) {
+    let len = core::mem::size_of::<T>();
+    let src = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, len) };
+    let mut offset = 0;
+    for chunk in translated_byte_buffer(token, ptr as *const u8, len) {
+        let end = offset + chunk.len();
+        chunk.copy_from_slice(&src[offset..end]);
+        offset = end;
+    }
+}
+
+/// Read a `T` back out of the user-space object at `ptr`. The inverse of
+/// [`copy_to_user`].
+pub fn copy_from_user<T: Copy>(token: usize, ptr: *const T) -> T {
+    let len = core::mem::size_of::<T>();
+    let mut bytes = vec![0u8; len];
+    let mut offset = 0;
+    for chunk in translated_byte_buffer(token, ptr as *const u8, len) {
+        let end = offset + chunk.len();
+        bytes[offset..end].copy_from_slice(chunk);
+        offset = end;
+    }
+    unsafe { (bytes.as_ptr() as *const T).read_unaligned() }
+}
+
 // /// for lab4
 // /// start和len都需要pagesize对齐
 // /// port是后8位置
@@ -1,22 +1,26 @@
 //! Process management syscalls
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
 
 use crate::{
     config::{PAGE_SIZE, MAX_SYSCALL_NUM},
     loader::get_app_data_by_name,
     mm::{translated_refmut, translated_str},
+    sync::UPSafeCell,
     task::{
         add_task, current_task, current_user_token, exit_current_and_run_next,
         suspend_current_and_run_next, TaskStatus,
         syscall_times_query,
-        running_time_query, map_inner
+        running_time_query, map_framed_inner, map_shared_inner
     },
     timer::get_time_us,
-    mm::*, 
+    mm::*,
 };
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct TimeVal {
     pub sec: usize,
     pub usec: usize,
@@ -24,6 +28,7 @@ pub struct TimeVal {
 
 /// Task information
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub struct TaskInfo {
     /// Task status in it's life cycle
     status: TaskStatus,
@@ -35,7 +40,9 @@ pub struct TaskInfo {
 
 /// task exits and submit an exit code
 pub fn sys_exit(exit_code: i32) -> ! {
-    trace!("kernel:pid[{}] sys_exit", current_task().unwrap().pid.0);
+    let pid = current_task().unwrap().pid.0;
+    trace!("kernel:pid[{}] sys_exit", pid);
+    reap_shm_attachments(pid);
     exit_current_and_run_next(exit_code);
     panic!("Unreachable in sys_exit!");
 }
@@ -118,40 +125,31 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
     // ---- release current PCB automatically
 }
 
-/// YOUR JOB: get time with second and microsecond
-/// HINT: You might reimplement it with virtual memory management.
-/// HINT: What if [`TimeVal`] is splitted by two pages ?
+/// get time with second and microsecond
 pub fn sys_get_time(_ts: *mut TimeVal, _tz: usize) -> isize {
     let us = get_time_us();
-    unsafe {
-        let pt = PageTable::from_token(current_user_token());
-        let _ts_va = VirtAddr::from(_ts as usize);
-        let _ts_vpn = _ts_va.floor();
-        let _ts_ppn = pt.translate(_ts_vpn).unwrap().ppn();
-        let _ts_1 = ((*(&_ts_ppn)).0 * PAGE_SIZE + _ts_va.page_offset()) as *mut u8 as *mut TimeVal;
-        // println!("DEBUG: get_time_us={}", us);
-        *_ts_1 = TimeVal {
+    copy_to_user(
+        current_user_token(),
+        _ts,
+        &TimeVal {
             sec: us / 1_000_000,
             usec: us % 1_000_000,
-        }; /* NOT CORRECT IN CHAPTER 4 */
-    }
+        },
+    );
     0
 }
 
-/// YOUR JOB: Finish sys_task_info to pass testcases
-/// HINT: You might reimplement it with virtual memory management.
-/// HINT: What if [`TaskInfo`] is splitted by two pages ?
+/// Finish sys_task_info to pass testcases
 pub fn sys_task_info(_ti: *mut TaskInfo) -> isize {
-    unsafe {
-        let pt = PageTable::from_token(current_user_token());
-        let _ti_va = VirtAddr::from(_ti as usize);
-        let _ti_vpn = _ti_va.floor();
-        let _ti_ppn = pt.translate(_ti_vpn).unwrap().ppn();
-        let _ti_1 = ((*(&_ti_ppn)).0 * PAGE_SIZE + _ti_va.page_offset()) as *mut u8 as *mut TaskInfo;
-        (*_ti_1).status = TaskStatus::Running;
-        (*_ti_1).syscall_times = syscall_times_query();
-        (*_ti_1).time = running_time_query();
-    } /* NOT CORRECT IN CHAPTER 4 */
+    copy_to_user(
+        current_user_token(),
+        _ti,
+        &TaskInfo {
+            status: TaskStatus::Running,
+            syscall_times: syscall_times_query(),
+            time: running_time_query(),
+        },
+    );
     0
 }
 
@@ -180,45 +178,29 @@ pub fn sys_mmap(_start: usize, _len: usize, _port: usize) -> isize {
         if flag_x != 0 {
             other_flags = other_flags | PTEFlags::X;
         }
-        // Check
+        // Check: reject if any page in the range is already mapped
         while binder < ( end / PAGE_SIZE + 1 ) * PAGE_SIZE {
-            // let _vpn = binder / PAGE_SIZE;
-            // println!("Checking binder = {}, end = {}", binder, end);
             let virt_page_num = VirtAddr(binder).into();
-            if let Some (ppn_exists) = pt.translate(virt_page_num) {
-                // println!("VirPG has PhyPG onto! and ppn is {}", ppn_exists.bits);
-                if ppn_exists.bits != 0 {
-                    return -1;
-                }
+            let resident = pt
+                .translate(virt_page_num)
+                .map(|pte| pte.is_valid())
+                .unwrap_or(false);
+            if resident {
+                return -1;
             }
             binder = binder + PAGE_SIZE;
         }
         let mut binder = _start;
-        // Allocate
+        // Allocate and map every page up front
         while binder < ( end / PAGE_SIZE + 1 ) * PAGE_SIZE {
-            // let _vpn = binder / PAGE_SIZE;
             let virt_page_num: VirtPageNum = VirtAddr(binder).into();
-            let mut ppn = PhysPageNum(0);
-            if let Some(ppn_wrapper) = frame_alloc() {
-                ppn = ppn_wrapper.ppn;
+            if map_framed_inner(virt_page_num, PTEFlags::U | other_flags).is_none() {
+                return -1;
             }
-            // println!("binder: {:#x}, virt_page_num.0: {:#x}, ppn.0: {:#x}", binder, virt_page_num.0, ppn.0);
-            // println!("DEBUG: Map: vpn.0={}, ppn.0={}.", virt_page_num.0, ppn.0);
-            map_inner(virt_page_num, ppn, PTEFlags::U | other_flags);
-
-            if let Some (ppn_exists) = pt.translate(virt_page_num) {
-                if ppn_exists.bits == 0 {
-                    // println!("ppn is 0, Map failed!");
-                }
-            }
-
             binder = binder + PAGE_SIZE;
         }
         0
     }
-
-    // trace!("kernel: sys_mmap NOT IMPLEMENTED YET!");
-    // -1
 }
 
 /// YOUR JOB: Implement munmap.
@@ -231,32 +213,76 @@ pub fn sys_munmap(_start: usize, _len: usize) -> isize {
 
         let mut binder = _start;
         let end = _start + _len - 1;
-        // Check
+        // Check: every page in the range must already be mapped (resident or lazy)
         while binder < ( end / PAGE_SIZE + 1 ) * PAGE_SIZE {
-            // let _vpn = binder / PAGE_SIZE;
-            // println!("Checking binder = {}, end = {}", binder, end);
             let virt_page_num = VirtAddr(binder).into();
-            if let Some (ppn_exists) = pt.translate(virt_page_num) {
-                // println!("binder: {:#x}, virt_page_num.0: {:#x}, ppn_exists.bits: {:#x}", binder, virt_page_num.0, ppn_exists.bits);
-                if ppn_exists.bits == 0 {
-                    // println!("ppn is 0, Error!");
-                    return -1;
-                }
+            let mapped = pt
+                .translate(virt_page_num)
+                .map(|pte| pte.is_valid() || pte.flags().contains(PTEFlags::LAZY))
+                .unwrap_or(false);
+            if !mapped {
+                return -1;
             }
             binder = binder + PAGE_SIZE;
         }
         let mut binder = _start;
-        // Allocate
         while binder < ( end / PAGE_SIZE + 1 ) * PAGE_SIZE {
-            // let _vpn = binder / PAGE_SIZE;
             let virt_page_num = VirtAddr(binder).into();
-            PageTable::unmap(&mut pt, virt_page_num);
+            pt.unmap_any(virt_page_num);
             binder = binder + PAGE_SIZE;
         }
         0
     }
 }
 
+/// Change the access permissions of an already-mapped region. The whole
+/// range is checked before any page is reflagged, so a failure never
+/// leaves it partially changed.
+pub fn sys_mprotect(_start: usize, _len: usize, _port: usize) -> isize {
+    if _port & 0x7 == 0 || _port & !0x7 != 0 || _start % PAGE_SIZE != 0 {
+        return -1;
+    }
+    let mut pt = PageTable::from_token(current_user_token());
+
+    let mut flags = PTEFlags::U;
+    if _port & 0x1 != 0 {
+        flags |= PTEFlags::R;
+    }
+    if _port & 0x2 != 0 {
+        flags |= PTEFlags::W;
+    }
+    if _port & 0x4 != 0 {
+        flags |= PTEFlags::X;
+    }
+
+    if _len == 0 {
+        return -1;
+    }
+    let end = _start + _len - 1;
+    let mut binder = _start;
+    // Check: every page in the range must already be mapped and not COW
+    // (reflagging a still-shared frame to W would corrupt the other holder)
+    while binder < ( end / PAGE_SIZE + 1 ) * PAGE_SIZE {
+        let virt_page_num = VirtAddr(binder).into();
+        let ok = pt
+            .translate(virt_page_num)
+            .map(|pte| pte.is_valid() && !pte.flags().contains(PTEFlags::COW))
+            .unwrap_or(false);
+        if !ok {
+            return -1;
+        }
+        binder = binder + PAGE_SIZE;
+    }
+    let mut binder = _start;
+    // Apply: safe to unwrap, the check above already guaranteed every page is mapped
+    while binder < ( end / PAGE_SIZE + 1 ) * PAGE_SIZE {
+        let virt_page_num = VirtAddr(binder).into();
+        pt.set_flags(virt_page_num, flags).unwrap();
+        binder = binder + PAGE_SIZE;
+    }
+    0
+}
+
 /// change data segment size
 pub fn sys_sbrk(size: i32) -> isize {
     trace!("kernel:pid[{}] sys_sbrk", current_task().unwrap().pid.0);
@@ -307,3 +333,213 @@ pub fn sys_set_priority(_prio: isize) -> isize {
         _prio
     }
 }
+
+/// One resident page captured in a coredump.
+pub struct DumpPage {
+    pub vpn: VirtPageNum,
+    pub flags: PTEFlags,
+    pub data: [u8; PAGE_SIZE],
+}
+
+/// A point-in-time snapshot of a task's address space: its trap context's
+/// pc/registers plus every resident page `PageTable::iter_mapped` can reach.
+/// No filesystem exists yet, so dumps are kept in an in-kernel table rather
+/// than written to a path.
+pub struct CoreDump {
+    pub pid: usize,
+    pub satp: usize,
+    pub pc: usize,
+    pub registers: [usize; 32],
+    pub pages: Vec<DumpPage>,
+}
+
+lazy_static! {
+    /// Dumps captured by [`dump_address_space`], keyed by pid so a task's
+    /// own dump is overwritten rather than accumulating.
+    static ref COREDUMPS: UPSafeCell<BTreeMap<usize, CoreDump>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+}
+
+/// Walk the current task's page table and trap context into a [`CoreDump`].
+///
+/// Only ever called from `sys_coredump` — no commit in this series touches
+/// the trap/fault path, so dumping on an unrecoverable user fault (the
+/// request's other stated use) isn't implemented.
+pub fn dump_address_space() -> CoreDump {
+    let task = current_task().unwrap();
+    let satp = task.inner_exclusive_access().memory_set.token();
+    let trap_cx = task.inner_exclusive_access().get_trap_cx();
+    let pc = trap_cx.sepc;
+    let registers = trap_cx.x;
+    let page_table = PageTable::from_token(satp);
+    let pages = page_table
+        .iter_mapped()
+        .map(|(vpn, pte)| {
+            let mut data = [0u8; PAGE_SIZE];
+            data.copy_from_slice(pte.ppn().get_bytes_array());
+            DumpPage {
+                vpn,
+                flags: pte.flags(),
+                data,
+            }
+        })
+        .collect();
+    CoreDump {
+        pid: task.pid.0,
+        satp,
+        pc,
+        registers,
+        pages,
+    }
+}
+
+/// Let a task request a dump of its own address space. `_path_ptr` is
+/// unused for now — dumps are kept one-per-pid rather than under a
+/// caller-supplied label, since an unbounded label-keyed table would let
+/// a task exhaust kernel heap by dumping itself in a loop.
+pub fn sys_coredump(_path_ptr: *const u8) -> isize {
+    let dump = dump_address_space();
+    COREDUMPS.exclusive_access().insert(dump.pid, dump);
+    0
+}
+
+/// A System-V-style shared memory segment: the frames backing it and how
+/// many address spaces currently have it attached.
+struct ShmSegment {
+    frames: Vec<FrameTracker>,
+    attached: usize,
+}
+
+lazy_static! {
+    static ref SHM_SEGMENTS: UPSafeCell<BTreeMap<usize, ShmSegment>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+    /// `key == 0` (IPC_PRIVATE-style) segments get an id from here instead
+    /// of reusing the key, so distinct anonymous segments never collide.
+    static ref NEXT_PRIVATE_SHM_ID: UPSafeCell<usize> = unsafe { UPSafeCell::new(1 << 32) };
+    /// `(pid, va) -> (id, page count)` for the attachments `sys_shmdt` undoes.
+    /// Keyed by pid, not `satp`, so a stale entry can't collide with whatever
+    /// new process is handed the exited attacher's freed root frame.
+    static ref SHM_ATTACHMENTS: UPSafeCell<BTreeMap<(usize, usize), (usize, usize)>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+}
+
+/// Get (creating if needed) a shared segment of at least `len` bytes.
+/// `key == 0` always creates a fresh, privately-keyed segment; a nonzero
+/// `key` returns the existing segment registered under it, if any, so
+/// cooperating processes can rendezvous on the same frames.
+pub fn sys_shmget(key: usize, len: usize) -> isize {
+    let mut segments = SHM_SEGMENTS.exclusive_access();
+    if key != 0 {
+        if segments.contains_key(&key) {
+            return key as isize;
+        }
+    }
+    let id = if key != 0 {
+        key
+    } else {
+        let mut next = NEXT_PRIVATE_SHM_ID.exclusive_access();
+        let id = *next;
+        *next += 1;
+        id
+    };
+    let npages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut frames = Vec::with_capacity(npages);
+    for _ in 0..npages {
+        match frame_alloc() {
+            Some(frame) => frames.push(frame),
+            None => return -1,
+        }
+    }
+    segments.insert(
+        id,
+        ShmSegment {
+            frames,
+            attached: 0,
+        },
+    );
+    id as isize
+}
+
+/// Map segment `id`'s frames into the calling task's address space at `addr`
+/// with the R/W/X/U permissions encoded in `port`, returning the attach
+/// address on success.
+pub fn sys_shmat(id: usize, addr: usize, port: usize) -> isize {
+    if port & 0x7 == 0 || port & !0x7 != 0 || addr % PAGE_SIZE != 0 {
+        return -1;
+    }
+    let mut flags = PTEFlags::U;
+    if port & 0x1 != 0 {
+        flags |= PTEFlags::R;
+    }
+    if port & 0x2 != 0 {
+        flags |= PTEFlags::W;
+    }
+    if port & 0x4 != 0 {
+        flags |= PTEFlags::X;
+    }
+
+    let pid = current_task().unwrap().pid.0;
+    let token = current_user_token();
+    let pt = PageTable::from_token(token);
+    let mut segments = SHM_SEGMENTS.exclusive_access();
+    let seg = match segments.get_mut(&id) {
+        Some(seg) => seg,
+        None => return -1,
+    };
+    // reject if any destination page is already mapped
+    for i in 0..seg.frames.len() {
+        let vpn = VirtAddr(addr + i * PAGE_SIZE).into();
+        if pt.translate(vpn).map(|pte| pte.is_valid()).unwrap_or(false) {
+            return -1;
+        }
+    }
+    for (i, frame) in seg.frames.iter().enumerate() {
+        let vpn = VirtAddr(addr + i * PAGE_SIZE).into();
+        map_shared_inner(vpn, frame.ppn, flags);
+    }
+    seg.attached += 1;
+    SHM_ATTACHMENTS
+        .exclusive_access()
+        .insert((pid, addr), (id, seg.frames.len()));
+    addr as isize
+}
+
+/// Detach the segment previously attached at `addr` by `sys_shmat`, freeing
+/// the frames once the last attacher has detached.
+pub fn sys_shmdt(addr: usize) -> isize {
+    let pid = current_task().unwrap().pid.0;
+    shmdt_inner(pid, addr).map(|_| 0).unwrap_or(-1)
+}
+
+/// Shared by `sys_shmdt` and `reap_shm_attachments`: unmap `addr`'s pages
+/// and drop the segment once the last attacher is gone.
+fn shmdt_inner(pid: usize, addr: usize) -> Option<()> {
+    let (id, npages) = SHM_ATTACHMENTS.exclusive_access().remove(&(pid, addr))?;
+    let mut pt = PageTable::from_token(current_user_token());
+    for i in 0..npages {
+        let vpn = VirtAddr(addr + i * PAGE_SIZE).into();
+        pt.unmap_any(vpn);
+    }
+    let mut segments = SHM_SEGMENTS.exclusive_access();
+    if let Some(seg) = segments.get_mut(&id) {
+        seg.attached -= 1;
+        if seg.attached == 0 {
+            // last holder: dropping the segment drops its FrameTrackers
+            segments.remove(&id);
+        }
+    }
+    Some(())
+}
+
+/// Detach every shm segment `pid` forgot to `sys_shmdt`. Called from `sys_exit`.
+fn reap_shm_attachments(pid: usize) {
+    let addrs: Vec<usize> = SHM_ATTACHMENTS
+        .exclusive_access()
+        .keys()
+        .filter(|(p, _)| *p == pid)
+        .map(|(_, addr)| *addr)
+        .collect();
+    for addr in addrs {
+        shmdt_inner(pid, addr);
+    }
+}